@@ -1,3 +1,4 @@
+use std::backtrace::Backtrace;
 use std::cmp::PartialEq;
 use std::fmt::{self, Display};
 use std::io;
@@ -8,6 +9,17 @@ use super::*;
 /// the PageCache.
 pub type CacheResult<T, Actual> = Result<T, Error<Actual>>;
 
+/// The result of a compare-and-swap operation against the `PageCache`.
+///
+/// On failure, the `Err` side carries both the `Actual` value that was
+/// observed in place of the expected one, and the caller's own `Rejected`
+/// write that failed to install. Having both in hand lets a CAS loop retry
+/// immediately on a contention miss, without re-reading the current state
+/// or re-allocating the rejected write. The outer `Option` is `None` when
+/// the thing being compared-and-swapped no longer exists to be compared
+/// against.
+pub type CasResult<T, Actual, Rejected> = Result<T, Option<(Actual, Rejected)>>;
+
 /// An Error type encapsulating various issues that may come up
 /// in both the expected and unexpected operation of a PageCache.
 #[derive(Debug)]
@@ -17,14 +29,27 @@ pub enum Error<Actual> {
     /// The system has been used in an unsupported way.
     Unsupported(String),
     /// An unexpected bug has happened. Please open an issue on github!
-    ReportableBug(String),
+    ReportableBug {
+        /// A description of the unexpected condition.
+        message: String,
+        /// A backtrace captured when the bug was detected, present when
+        /// `RUST_BACKTRACE` is set.
+        backtrace: Backtrace,
+    },
     /// A read or write error has happened when interacting with the file system.
     Io(io::Error),
     /// Corruption has been detected in the storage file.
     Corruption {
         /// The file location that corrupted data was found at.
         at: LogID,
+        /// A backtrace captured when the corruption was detected, present
+        /// when `RUST_BACKTRACE` is set.
+        backtrace: Backtrace,
     },
+    /// A tree or other collection that was expected to exist is gone.
+    CollectionNotFound(Vec<u8>),
+    /// The requested page has been freed and no longer exists.
+    PageNotFound(PageID),
 }
 use Error::*;
 
@@ -47,18 +72,23 @@ impl<A> PartialEq for Error<A>
                     false
                 }
             }
-            &ReportableBug(ref l) => {
-                if let &ReportableBug(ref r) = other {
+            &ReportableBug {
+                message: ref l, ..
+            } => {
+                if let &ReportableBug {
+                    message: ref r, ..
+                } = other
+                {
                     l == r
                 } else {
                     false
                 }
             }
             &Corruption {
-                at: l,
+                at: l, ..
             } => {
                 if let &Corruption {
-                    at: r,
+                    at: r, ..
                 } = other
                 {
                     l == r
@@ -66,6 +96,20 @@ impl<A> PartialEq for Error<A>
                     false
                 }
             }
+            &CollectionNotFound(ref l) => {
+                if let &CollectionNotFound(ref r) = other {
+                    l == r
+                } else {
+                    false
+                }
+            }
+            &PageNotFound(ref l) => {
+                if let &PageNotFound(ref r) = other {
+                    l == r
+                } else {
+                    false
+                }
+            }
             &Io(_) => false,
         }
     }
@@ -89,21 +133,98 @@ impl<A> Display for Error<A>
             Unsupported(ref e) => {
                 write!(f, "Unsupported: {}", e)
             }
-            ReportableBug(ref e) => {
-                write!(f, "Unexpected bug has happened: {}", e)
+            ReportableBug { ref message, ref backtrace } => {
+                write!(f, "Unexpected bug has happened: {}", message)?;
+                if backtrace.status() == std::backtrace::BacktraceStatus::Captured {
+                    write!(f, "\n{}", backtrace)?;
+                }
+                Ok(())
             }
             Io(ref e) => {
                 write!(f, "IO error: {}", e)
             }
-            Corruption { at } => {
-                write!(f, "Corruption at: {}", at)
-            }     
+            Corruption { at, ref backtrace } => {
+                write!(f, "Corruption at: {}", at)?;
+                if backtrace.status() == std::backtrace::BacktraceStatus::Captured {
+                    write!(f, "\n{}", backtrace)?;
+                }
+                Ok(())
+            }
+            CollectionNotFound(ref name) => {
+                write!(f, "Collection not found: {:?}", name)
+            }
+            PageNotFound(pid) => {
+                write!(f, "Page not found: {}", pid)
+            }
+        }
+    }
+}
+
+impl<A> std::error::Error for Error<A>
+    where A: fmt::Debug,
+{
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            Io(ref e) => Some(e),
+            _ => None,
         }
     }
 }
 
 // TODO wrangle Into conflicts to handle these with that, if possible
 impl<T> Error<T> {
+    /// Constructs a `ReportableBug` error, capturing a backtrace if
+    /// `RUST_BACKTRACE` is set (capture is a no-op otherwise).
+    pub fn reportable_bug<S: Into<String>>(message: S) -> Error<T> {
+        ReportableBug {
+            message: message.into(),
+            backtrace: Backtrace::capture(),
+        }
+    }
+
+    /// Constructs a `Corruption` error at the given location, capturing a
+    /// backtrace if `RUST_BACKTRACE` is set (capture is a no-op otherwise).
+    pub fn corruption(at: LogID) -> Error<T> {
+        Corruption {
+            at,
+            backtrace: Backtrace::capture(),
+        }
+    }
+
+    /// Returns the backtrace captured when this error was constructed, if
+    /// this variant captures one and `RUST_BACKTRACE` was set.
+    pub fn backtrace(&self) -> Option<&Backtrace> {
+        match *self {
+            ReportableBug { ref backtrace, .. } |
+            Corruption { ref backtrace, .. }
+                if backtrace.status() == std::backtrace::BacktraceStatus::Captured =>
+            {
+                Some(backtrace)
+            }
+            _ => None,
+        }
+    }
+
+    /// Resolves a page-cache CAS attempt into this crate's standard error
+    /// type. A CAS that lost to a current value becomes `CasFailed`, and the
+    /// caller gets its unconsumed `Rejected` write back so a retry doesn't
+    /// need to re-allocate. A CAS whose target no longer exists becomes
+    /// `Unsupported`, with no `Rejected` write to hand back.
+    pub fn from_cas_result<Res, Rejected>(
+        result: CasResult<Res, T, Rejected>,
+    ) -> Result<Res, (Error<T>, Option<Rejected>)> {
+        match result {
+            Ok(res) => Ok(res),
+            Err(Some((actual, rejected))) => Err((CasFailed(actual), Some(rejected))),
+            Err(None) => {
+                Err((
+                    Unsupported("compare-and-swap target no longer exists".to_owned()),
+                    None,
+                ))
+            }
+        }
+    }
+
     /// Turns an `Error<A>` into an `Error<B>`.
     ///
     /// # Panics
@@ -117,13 +238,17 @@ impl<T> Error<T> {
                 )
             }
             Unsupported(s) => Unsupported(s),
-            ReportableBug(s) => ReportableBug(s),
+            ReportableBug { message, backtrace } => ReportableBug { message, backtrace },
             Io(e) => Io(e),
             Corruption {
                 at,
+                backtrace,
             } => Corruption {
                 at,
+                backtrace,
             },
+            CollectionNotFound(name) => CollectionNotFound(name),
+            PageNotFound(pid) => PageNotFound(pid),
         }
     }
 
@@ -134,13 +259,17 @@ impl<T> Error<T> {
         match self {
             CasFailed(other) => CasFailed(other.into()),
             Unsupported(s) => Unsupported(s),
-            ReportableBug(s) => ReportableBug(s),
+            ReportableBug { message, backtrace } => ReportableBug { message, backtrace },
             Io(e) => Io(e),
             Corruption {
                 at,
+                backtrace,
             } => Corruption {
                 at,
+                backtrace,
             },
+            CollectionNotFound(name) => CollectionNotFound(name),
+            PageNotFound(pid) => PageNotFound(pid),
         }
     }
 }
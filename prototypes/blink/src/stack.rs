@@ -1,29 +1,153 @@
 // lock-free stack
+use std::alloc::{self, Layout};
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::fmt::{self, Debug};
 use std::ptr;
 use std::mem;
 use std::ops::Deref;
 use std::sync::Arc;
-use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use std::sync::Mutex;
 
 pub struct Node<T> {
     inner: T,
     next: *mut Node<T>,
 }
 
+/// How many epoch generations of unlinked nodes we keep around before it's
+/// safe to actually free them. A node unlinked while the global epoch is
+/// `e` is only reclaimed once the epoch has advanced to `e + 2`, which
+/// guarantees that any thread which may still have had a pointer to it
+/// from before the unlink has since pinned at a later epoch and moved on.
+const EPOCH_GENERATIONS: usize = 3;
+
+/// Sentinel announced-epoch value for a thread that is not currently
+/// pinned.
+const UNPINNED: usize = usize::max_value();
+
+/// A node that has been unlinked from a `Stack` but not yet freed. The
+/// epoch it was retired in is recorded by which generation bucket of the
+/// `Stack`'s garbage it is queued in.
+struct Garbage<T>(*mut Node<T>);
+
+// Safety: a `Garbage` is only ever dereferenced again during reclamation,
+// by which point the epoch guarantees no other thread can still be
+// reading it, so it's sound to move it to whichever thread ends up
+// running the epoch-advancing reclamation.
+unsafe impl<T> Send for Garbage<T> {}
+
+/// Tracks a global epoch counter and the announced epoch of every thread
+/// that has pinned against it, so that unlinked nodes can be reclaimed
+/// only once it's provably safe to do so.
+struct Epoch {
+    current: AtomicUsize,
+    participants: Mutex<Vec<Arc<AtomicUsize>>>,
+}
+
+impl Epoch {
+    fn new() -> Epoch {
+        Epoch {
+            current: AtomicUsize::new(0),
+            participants: Mutex::new(vec![]),
+        }
+    }
+
+    // Each thread lazily registers (and keeps forever) one announced-epoch
+    // slot per `Epoch` it has pinned against, keyed by that `Epoch`'s
+    // address.
+    fn participant(&self) -> Arc<AtomicUsize> {
+        thread_local! {
+            static SLOTS: RefCell<HashMap<usize, Arc<AtomicUsize>>> =
+                RefCell::new(HashMap::new());
+        }
+        let key = self as *const Epoch as usize;
+        SLOTS.with(|slots| {
+            slots
+                .borrow_mut()
+                .entry(key)
+                .or_insert_with(|| {
+                    let slot = Arc::new(AtomicUsize::new(UNPINNED));
+                    self.participants.lock().unwrap().push(slot.clone());
+                    slot
+                })
+                .clone()
+        })
+    }
+
+    /// Pins the current thread to the current epoch until the returned
+    /// `Guard` is dropped, announcing it so reclamation can't run past it.
+    fn pin(&self) -> Guard {
+        let slot = self.participant();
+        let epoch = self.current.load(Ordering::SeqCst);
+        slot.store(epoch, Ordering::SeqCst);
+        Guard {
+            slot: slot,
+        }
+    }
+
+    /// If every pinned participant has caught up to the current epoch,
+    /// advances it by one and returns the generation bucket that is now
+    /// two generations behind and safe to reclaim.
+    fn try_advance(&self) -> Option<usize> {
+        let current = self.current.load(Ordering::SeqCst);
+        let participants = self.participants.lock().unwrap();
+        let all_caught_up = participants.iter().all(|slot| {
+            let seen = slot.load(Ordering::SeqCst);
+            seen == UNPINNED || seen == current
+        });
+        if !all_caught_up {
+            return None;
+        }
+        if self.current.compare_and_swap(current, current + 1, Ordering::SeqCst) != current {
+            return None;
+        }
+        Some((current + EPOCH_GENERATIONS - 1) % EPOCH_GENERATIONS)
+    }
+}
+
+/// A pin on an `Epoch`, held for the duration of a single lock-free
+/// operation. Dropping it un-announces the thread.
+struct Guard {
+    slot: Arc<AtomicUsize>,
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        self.slot.store(UNPINNED, Ordering::SeqCst);
+    }
+}
+
+// Safety: `inner` has already been read out of the node by the time it's
+// queued here, and the memory itself is only freed, never re-read as a
+// `T`, so no destructor for `T` runs over it.
+unsafe fn free_node<T>(ptr: *mut Node<T>) {
+    let layout = Layout::for_value(unsafe { &*ptr });
+    unsafe {
+        alloc::dealloc(ptr as *mut u8, layout);
+    }
+}
+
 #[derive(Clone)]
 pub struct Stack<T> {
     head: Arc<AtomicPtr<Node<T>>>,
+    epoch: Arc<Epoch>,
+    garbage: Arc<Mutex<[Vec<Garbage<T>>; EPOCH_GENERATIONS]>>,
 }
 
 impl<T> Default for Stack<T> {
     fn default() -> Stack<T> {
-        Stack { head: Arc::new(AtomicPtr::new(ptr::null_mut())) }
+        Stack {
+            head: Arc::new(AtomicPtr::new(ptr::null_mut())),
+            epoch: Arc::new(Epoch::new()),
+            garbage: Arc::new(Mutex::new([Vec::new(), Vec::new(), Vec::new()])),
+        }
     }
 }
 
 impl<T: Debug> Debug for Stack<T> {
     fn fmt(&self, formatter: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let _guard = self.epoch.pin();
         let mut ptr = self.head();
         formatter.write_str("Stack [").unwrap();
         let mut written = false;
@@ -62,6 +186,13 @@ impl<T> Drop for Stack<T> {
             let node = unsafe { Box::from_raw(ptr) };
             ptr = node.next;
         }
+        for bucket in self.garbage.lock().unwrap().iter_mut() {
+            for Garbage(ptr) in bucket.drain(..) {
+                unsafe {
+                    free_node(ptr);
+                }
+            }
+        }
     }
 }
 
@@ -85,28 +216,38 @@ impl<T> Stack<T> {
     }
 
     pub fn try_pop(&self) -> Result<Option<T>, ()> {
+        let _guard = self.epoch.pin();
+
         let head_ptr = self.head();
         if head_ptr.is_null() {
             return Ok(None);
         }
-        let node = unsafe { Box::from_raw(head_ptr) };
-        let next_ptr = node.next;
+
+        // Safe to read through `head_ptr` while pinned: even if another
+        // thread concurrently unlinks it, reclamation can't run past our
+        // announced epoch, so the node can't have been freed yet.
+        let next_ptr = unsafe { (*head_ptr).next };
 
         if head_ptr == self.head.compare_and_swap(head_ptr, next_ptr, Ordering::SeqCst) {
-            Ok(Some(node.inner))
+            let inner = unsafe { ptr::read(&(*head_ptr).inner) };
+            self.retire(head_ptr);
+            Ok(Some(inner))
         } else {
-            mem::forget(node);
             Err(())
         }
     }
 
     pub fn pop_all(&self) -> Vec<T> {
+        let _guard = self.epoch.pin();
+
         let mut res = vec![];
         let mut node_ptr = self.head.swap(ptr::null_mut(), Ordering::SeqCst);
         while !node_ptr.is_null() {
-            let node = unsafe { Box::from_raw(node_ptr) };
-            node_ptr = node.next;
-            res.push(node.inner);
+            let next_ptr = unsafe { (*node_ptr).next };
+            let inner = unsafe { ptr::read(&(*node_ptr).inner) };
+            res.push(inner);
+            self.retire(node_ptr);
+            node_ptr = next_ptr;
         }
         res
     }
@@ -127,6 +268,35 @@ impl<T> Stack<T> {
     pub fn head(&self) -> *mut Node<T> {
         self.head.load(Ordering::SeqCst)
     }
+
+    // Queues an unlinked node for reclamation once every thread has moved
+    // past the epoch it was unlinked in, then opportunistically advances
+    // the epoch and frees whatever just became safe to free.
+    //
+    // Must tag with the *current* global epoch, not the epoch the caller
+    // originally pinned at: a pinned thread can stay at epoch `e` while
+    // the global epoch advances to `e + 1` (a pin at `e` satisfies the
+    // "caught up" check that lets others advance `e` -> `e + 1`), so by
+    // the time this node is actually unlinked the true epoch may already
+    // be ahead of the guard. Tagging with the stale pin epoch would let
+    // the node be reclaimed one generation too early, while a concurrent
+    // reader that loaded it just before the unlink is still dereferencing
+    // it.
+    fn retire(&self, ptr: *mut Node<T>) {
+        let epoch = self.epoch.current.load(Ordering::SeqCst);
+        let bucket = epoch % EPOCH_GENERATIONS;
+        self.garbage.lock().unwrap()[bucket].push(Garbage(ptr));
+
+        if let Some(reclaimable) = self.epoch.try_advance() {
+            let garbage =
+                mem::replace(&mut self.garbage.lock().unwrap()[reclaimable], Vec::new());
+            for Garbage(ptr) in garbage {
+                unsafe {
+                    free_node(ptr);
+                }
+            }
+        }
+    }
 }
 
 #[test]
@@ -158,4 +328,4 @@ fn basic_functionality() {
         assert_eq!(ll4.try_pop(), Ok(None));
     });
     t.join().unwrap();
-}
\ No newline at end of file
+}